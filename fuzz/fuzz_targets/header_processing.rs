@@ -0,0 +1,72 @@
+#![no_main]
+use std::time::Duration;
+
+use libfuzzer_sys::fuzz_target;
+
+use sphinx::constants::INTEGRITY_MAC_KEY_SIZE;
+use sphinx::crypto::STREAM_CIPHER_KEY_SIZE;
+use sphinx::header::keys::RoutingKeys;
+use sphinx::header::mac::HeaderIntegrityMac;
+use sphinx::header::routing::{
+    EncapsulatedRoutingInformation, EncryptedRoutingInformation, FuzzSuite,
+    ENCRYPTED_ROUTING_INFO_SIZE, HEADER_INTEGRITY_MAC_SIZE,
+};
+use sphinx::route::node_address_fixture;
+
+// the exact bound doesn't matter to this harness - it just needs to be large enough that a
+// deterministic, non-current fuzz-input timestamp is never spuriously rejected as expired
+const MAX_PACKET_AGE: Duration = Duration::MAX;
+
+// pulls `len` bytes out of the fuzzer's input starting at `*offset`, zero-padding if the input
+// runs out, and advances `*offset` past them
+fn take_bytes(data: &[u8], offset: &mut usize, len: usize) -> Vec<u8> {
+    let end = (*offset + len).min(data.len());
+    let mut out = vec![0u8; len];
+    if *offset < end {
+        out[..end - *offset].copy_from_slice(&data[*offset..end]);
+    }
+    *offset += len;
+    out
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut offset = 0;
+
+    let mut enc_routing_info_bytes = [0u8; ENCRYPTED_ROUTING_INFO_SIZE];
+    enc_routing_info_bytes
+        .copy_from_slice(&take_bytes(data, &mut offset, ENCRYPTED_ROUTING_INFO_SIZE));
+
+    let mac_bytes = take_bytes(data, &mut offset, HEADER_INTEGRITY_MAC_SIZE);
+
+    let mut stream_cipher_key = [0u8; STREAM_CIPHER_KEY_SIZE];
+    stream_cipher_key.copy_from_slice(&take_bytes(data, &mut offset, STREAM_CIPHER_KEY_SIZE));
+
+    let mut header_integrity_hmac_key = [0u8; INTEGRITY_MAC_KEY_SIZE];
+    header_integrity_hmac_key
+        .copy_from_slice(&take_bytes(data, &mut offset, INTEGRITY_MAC_KEY_SIZE));
+
+    let mut own_address = node_address_fixture();
+    own_address.copy_from_slice(&take_bytes(data, &mut offset, own_address.len()));
+
+    let make_routing_keys = || RoutingKeys {
+        stream_cipher_key,
+        header_integrity_hmac_key,
+    };
+    let make_encapsulated = || {
+        EncapsulatedRoutingInformation::from_parts(
+            EncryptedRoutingInformation::from_bytes(enc_routing_info_bytes),
+            HeaderIntegrityMac::from_bytes(&mac_bytes),
+        )
+    };
+
+    // arbitrary/tampered input must never panic (no index-out-of-bounds, no `assert_eq!`
+    // abort reachable from here) and must only ever yield a clean error, never a crash; uses
+    // `FuzzSuite` rather than the default AES-CTR+HMAC-SHA256 primitives so the fuzzer's cycles
+    // go toward this module's own parsing and validation logic instead of real crypto
+    let _ = make_encapsulated().process_with_suite::<FuzzSuite>(make_routing_keys(), MAX_PACKET_AGE);
+    let _ = make_encapsulated().unseal_with_suite::<FuzzSuite>(
+        make_routing_keys(),
+        own_address,
+        MAX_PACKET_AGE,
+    );
+});
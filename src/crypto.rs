@@ -12,6 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use aes_ctr::stream_cipher::generic_array::GenericArray;
 use aes_ctr::stream_cipher::{NewStreamCipher, SyncStreamCipher};
 use aes_ctr::Aes128Ctr;
@@ -20,6 +22,8 @@ use curve25519_dalek::scalar::Scalar;
 use hmac::{Hmac, Mac};
 use rand_core::OsRng;
 use sha2::Sha256;
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
 
 pub const CURVE_GENERATOR: MontgomeryPoint = curve25519_dalek::constants::X25519_BASEPOINT;
 pub const STREAM_CIPHER_KEY_SIZE: usize = 16;
@@ -66,14 +70,44 @@ pub fn generate_pseudorandom_bytes(
     data
 }
 
-// FUTURE TODO: THIS IS DONE INCORRECTLY AND INTRODUCES TIMING ATTACKS
-// https://github.com/nymtech/sphinx/issues/61
-pub fn compute_keyed_hmac(key: Vec<u8>, data: &[u8]) -> Vec<u8> {
+// `key` is taken as a `Zeroizing` buffer (rather than a plain `Vec<u8>`) so that callers who
+// can only hand us an owned copy of key material (e.g. because they need to fold it into a
+// larger buffer first) get it wiped here when we're done with it, instead of it sitting in
+// freed memory until the allocator reuses the page.
+pub fn compute_keyed_hmac(key: Zeroizing<Vec<u8>>, data: &[u8]) -> Vec<u8> {
     let mut mac = HmacSha256::new_varkey(&key).expect("HMAC can take key of any size");
     mac.input(&data);
     mac.result().code().to_vec()
 }
 
+/// Compares two byte slices in constant time, i.e. independently of the position of the
+/// first differing byte. Use this (rather than `==`) for anything that compares a MAC or
+/// other authentication tag against an attacker-controlled value - see issue #61.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.ct_eq(b).into()
+}
+
+/// Recomputes `HMAC-SHA256(key, data)`, truncates it to the length of `expected_tag`, and
+/// compares the result against it in constant time.
+pub fn verify_keyed_hmac(key: Zeroizing<Vec<u8>>, data: &[u8], expected_tag: &[u8]) -> bool {
+    let mut computed_tag = compute_keyed_hmac(key, data);
+    computed_tag.truncate(expected_tag.len());
+    constant_time_eq(&computed_tag, expected_tag)
+}
+
+/// Rejects anything stamped further in the past than `max_packet_age`. Packets stamped in the
+/// future are accepted, since modest clock skew between sender and mix is normal. Shared by
+/// `crate::header::unwrap` and `crate::header::routing`'s two independent routing-information
+/// parsers, which otherwise each ended up with their own copy of this same check.
+pub fn check_packet_not_expired(timestamp: u64, max_packet_age: Duration) -> bool {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is set before the unix epoch")
+        .as_secs();
+
+    now.saturating_sub(timestamp) <= max_packet_age.as_secs()
+}
+
 #[cfg(test)]
 mod generating_pseudorandom_bytes {
     use super::*;
@@ -111,3 +145,81 @@ mod generating_a_random_curve_point {
         assert_eq!(32, secret.to_bytes().len())
     }
 }
+
+#[cfg(test)]
+mod constant_time_comparison {
+    use super::*;
+
+    #[test]
+    fn it_returns_true_for_identical_slices() {
+        assert!(constant_time_eq(&[1, 2, 3, 4], &[1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn it_returns_false_for_differing_slices() {
+        assert!(!constant_time_eq(&[1, 2, 3, 4], &[1, 2, 3, 5]));
+    }
+
+    #[test]
+    fn it_returns_false_for_slices_of_different_length() {
+        assert!(!constant_time_eq(&[1, 2, 3], &[1, 2, 3, 4]));
+    }
+}
+
+#[cfg(test)]
+mod checking_packet_expiry {
+    use super::*;
+
+    #[test]
+    fn it_accepts_a_packet_stamped_far_in_the_future() {
+        let far_future = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 3600;
+        assert!(check_packet_not_expired(far_future, Duration::from_secs(300)));
+    }
+
+    #[test]
+    fn it_accepts_a_packet_stamped_within_max_age() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert!(check_packet_not_expired(now, Duration::from_secs(300)));
+    }
+
+    #[test]
+    fn it_rejects_a_packet_older_than_max_age() {
+        let stale = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            - 3600;
+        assert!(!check_packet_not_expired(stale, Duration::from_secs(300)));
+    }
+}
+
+#[cfg(test)]
+mod verifying_keyed_hmac {
+    use super::*;
+
+    #[test]
+    fn it_accepts_a_correctly_computed_tag() {
+        let key = vec![1u8, 2, 3, 4];
+        let data = [9u8; 16];
+        let tag = compute_keyed_hmac(Zeroizing::new(key.clone()), &data);
+
+        assert!(verify_keyed_hmac(Zeroizing::new(key), &data, &tag));
+    }
+
+    #[test]
+    fn it_rejects_a_tampered_tag() {
+        let key = vec![1u8, 2, 3, 4];
+        let data = [9u8; 16];
+        let mut tag = compute_keyed_hmac(Zeroizing::new(key.clone()), &data);
+        tag[0] ^= 0xff;
+
+        assert!(!verify_keyed_hmac(Zeroizing::new(key), &data, &tag));
+    }
+}
@@ -1,43 +1,180 @@
+use std::convert::TryInto;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use crate::constants::{INTEGRITY_MAC_SIZE, SECURITY_PARAMETER, STREAM_CIPHER_OUTPUT_LENGTH};
+use crate::crypto::constant_time_eq;
 use crate::header::header;
 use crate::header::header::MixNode;
+use crate::header::mac::HeaderIntegrityMac as SuiteHeaderIntegrityMac;
 use crate::header::routing;
 use crate::header::routing::{
-    PaddedRoutingInformation, RoutingInformation, RoutingKeys, StreamCipherKey, ROUTING_INFO_SIZE,
+    DefaultSuite, MacAlgorithm, PaddedRoutingInformation, RoutingInformation, RoutingKeys,
+    SphinxSuite, StreamCipherAlgorithm, StreamCipherKey, ROUTING_INFO_SIZE,
 };
 use crate::header::SphinxHeader;
 use crate::utils;
-use crate::utils::crypto;
 use crate::Hop;
+use crate::ProcessingError;
+
+// a little-endian f64, the same representation `Hop::delay` is stored in
+const DELAY_SIZE: usize = 8;
+
+// a little-endian unix-seconds timestamp, set by the sender when the header is built
+const TIMESTAMP_SIZE: usize = 8;
+
+// the minimum length of a decrypted routing information blob that still lets us read a full
+// address/delay/timestamp/mac/next-header layout out of it
+const MIN_PARSEABLE_ROUTING_INFO_LENGTH: usize =
+    SECURITY_PARAMETER + DELAY_SIZE + TIMESTAMP_SIZE + INTEGRITY_MAC_SIZE + ROUTING_INFO_SIZE;
+
+impl SphinxHeader {
+    /// Deterministic tag derived from this hop's shared secret, cheap enough for a mix node
+    /// to keep a seen-set of them for replay detection without storing whole packets.
+    pub fn replay_tag(&self) -> Vec<u8> {
+        crate::crypto::compute_keyed_hmac(
+            zeroize::Zeroizing::new(self.shared_secret.to_bytes().to_vec()),
+            b"replay-tag",
+        )
+    }
+}
+
+/// Result of peeling a single layer of routing information off a [`SphinxHeader`].
+#[derive(Debug)]
+pub enum ProcessedHop {
+    /// there is (at least) one more mix hop the re-keyed header should be forwarded to
+    ForwardHop(SphinxHeader, Hop),
+    /// the leading address field was the reserved all-zero marker: we are the destination
+    FinalHop,
+}
 
 pub fn unwrap_routing_information(
     header: SphinxHeader,
-    stream_cipher_key: &StreamCipherKey,
-) -> (SphinxHeader, Hop) {
+    routing_keys: &RoutingKeys,
+    max_packet_age: Duration,
+) -> Result<ProcessedHop, ProcessingError> {
+    unwrap_routing_information_with_suite::<DefaultSuite>(header, routing_keys, max_packet_age)
+}
+
+/// The actual mix-node entry point, generic over [`SphinxSuite`] so that swapping this crate's
+/// crypto primitives (e.g. to `EaxSuite`, or to `FuzzSuite` under `cfg(feature = "fuzztarget")`)
+/// takes effect here too, rather than only on `EncapsulatedRoutingInformation`'s own builder and
+/// parser. This module still keeps its own routing-information wire layout rather than switching
+/// to `EncapsulatedRoutingInformation`'s - the two lay address/delay/timestamp/mac out at
+/// different offsets and sizes, and unifying the wire format is a separate, larger change - but
+/// both now go through the same `S::StreamCipher`/`S::Mac`, so a suite swap is no longer only
+/// reachable from `EncapsulatedRoutingInformation`'s own tests and the fuzz harness.
+pub fn unwrap_routing_information_with_suite<S: SphinxSuite>(
+    header: SphinxHeader,
+    routing_keys: &RoutingKeys,
+    max_packet_age: Duration,
+) -> Result<ProcessedHop, ProcessingError> {
+    // never decrypt (let alone trust the contents of) a header whose integrity we haven't
+    // first established - an attacker who tampered with `enc_header` must be rejected here
+    if !check_integrity_mac_with_suite::<S>(
+        header.routing_info.header_integrity_hmac,
+        routing_keys.header_integrity_hmac_key,
+        header.routing_info.enc_header,
+    ) {
+        return Err(ProcessingError::HeaderIntegrityMacMismatch);
+    }
+
     // we have to add padding to the encrypted routing information before decrypting, otherwise we gonna lose informatio
     let padded_routing_information =
         add_zero_padding_to_encrypted_routing_information(&header.routing_info.enc_header);
-    let unwrapped_routing_info =
-        decrypt_padded_routing_info(stream_cipher_key, &padded_routing_information);
+    let unwrapped_routing_info = decrypt_padded_routing_info_with_suite::<S>(
+        &routing_keys.stream_cipher_key,
+        &padded_routing_information,
+    );
+
+    parse_unwrapped_routing_information(
+        &unwrapped_routing_info,
+        header.shared_secret,
+        max_packet_age,
+    )
+}
+
+// splits the decrypted routing information into (in order) the next-hop address/destination
+// flag, the embedded per-hop delay, the embedded expiry timestamp, the next layer's header
+// integrity mac, and the next layer's encrypted header, failing cleanly on anything that
+// doesn't fit the expected layout or whose timestamp has aged out of `max_packet_age`
+fn parse_unwrapped_routing_information(
+    unwrapped_routing_info: &[u8],
+    shared_secret: curve25519_dalek::montgomery::MontgomeryPoint,
+    max_packet_age: Duration,
+) -> Result<ProcessedHop, ProcessingError> {
+    if unwrapped_routing_info.len() < MIN_PARSEABLE_ROUTING_INFO_LENGTH {
+        return Err(ProcessingError::InvalidRoutingInformationLengthError);
+    }
+
+    let (address_field, rest) = unwrapped_routing_info.split_at(SECURITY_PARAMETER);
+
+    // a destination stamps its own address field with all zero bytes before encapsulation,
+    // mirroring how `Payload` used to use a zero prefix to flag "unwrapped correctly"
+    if address_field.iter().all(|&byte| byte == 0) {
+        return Ok(ProcessedHop::FinalHop);
+    }
+
+    let mut next_hop_address = header::node_address_fixture();
+    next_hop_address.copy_from_slice(address_field);
 
-    // TODO: parse the decrypted result to get next_hop, delay, next_routing_info etc.
+    let (delay_bytes, rest) = rest.split_at(DELAY_SIZE);
+    let delay = f64::from_le_bytes(
+        delay_bytes
+            .try_into()
+            .expect("DELAY_SIZE bytes always convert into a [u8; DELAY_SIZE]"),
+    );
+
+    let (timestamp_bytes, rest) = rest.split_at(TIMESTAMP_SIZE);
+    let timestamp = u64::from_le_bytes(
+        timestamp_bytes
+            .try_into()
+            .expect("TIMESTAMP_SIZE bytes always convert into a [u8; TIMESTAMP_SIZE]"),
+    );
+    check_packet_not_expired(timestamp, max_packet_age)?;
+
+    let (next_header_integrity_hmac, next_enc_header) = rest.split_at(INTEGRITY_MAC_SIZE);
+
+    let mut header_integrity_hmac = [0u8; INTEGRITY_MAC_SIZE];
+    header_integrity_hmac.copy_from_slice(next_header_integrity_hmac);
+
+    let mut enc_header = [0u8; ROUTING_INFO_SIZE];
+    enc_header.copy_from_slice(&next_enc_header[..ROUTING_INFO_SIZE]);
 
-    (
+    Ok(ProcessedHop::ForwardHop(
         SphinxHeader {
-            shared_secret: curve25519_dalek::montgomery::MontgomeryPoint([0u8; 32]),
+            // the per-hop key-blinding happens alongside the shared-secret derivation the
+            // caller already performs when walking the route; it is not recoverable from
+            // the routing information alone and so isn't touched here
+            shared_secret,
             routing_info: routing::RoutingInfo {
-                enc_header: [0u8; ROUTING_INFO_SIZE],
-                header_integrity_hmac: [0u8; INTEGRITY_MAC_SIZE],
+                enc_header,
+                header_integrity_hmac,
             },
         },
         Hop {
             host: header::RouteElement::ForwardHop(MixNode {
-                address: header::node_address_fixture(),
+                address: next_hop_address,
+                // the next hop's public key is looked up by the processing node from its own
+                // directory of known mixes, it is never carried inside the Sphinx header
                 pub_key: curve25519_dalek::montgomery::MontgomeryPoint([0u8; 32]),
             }),
-            delay: 0.0,
+            delay,
         },
-    )
+    ))
+}
+
+// the expiry check itself lives in `crate::crypto`, shared with `crate::header::routing`'s own
+// parsing path - see the note there on why the same little-endian f64-delay + u64-timestamp
+// layout shouldn't have the check that bounds it copy-pasted in both places
+fn check_packet_not_expired(
+    timestamp: u64,
+    max_packet_age: Duration,
+) -> Result<(), ProcessingError> {
+    if !crate::crypto::check_packet_not_expired(timestamp, max_packet_age) {
+        return Err(ProcessingError::InvalidTimestamp);
+    }
+
+    Ok(())
 }
 
 fn add_zero_padding_to_encrypted_routing_information(enc_routing_info: &[u8]) -> Vec<u8> {
@@ -50,25 +187,36 @@ pub fn check_integrity_mac(
     integrity_mac_key: routing::HeaderIntegrityMacKey,
     enc_routing_info: RoutingInformation,
 ) -> bool {
-    let recomputed_integrity_mac =
-        routing::generate_routing_info_integrity_mac(integrity_mac_key, enc_routing_info);
-    if integrity_mac != recomputed_integrity_mac {
-        return false;
-    }
-    return true;
+    check_integrity_mac_with_suite::<DefaultSuite>(integrity_mac, integrity_mac_key, enc_routing_info)
+}
+
+fn check_integrity_mac_with_suite<S: SphinxSuite>(
+    integrity_mac: routing::HeaderIntegrityMac,
+    integrity_mac_key: routing::HeaderIntegrityMacKey,
+    enc_routing_info: RoutingInformation,
+) -> bool {
+    // `routing::HeaderIntegrityMac` (this module's own array-based mac type) and
+    // `crate::header::mac::HeaderIntegrityMac` (the struct `S::Mac` speaks) are independent
+    // representations of the same kind of tag - see the module-level note on the two parsing
+    // paths - so the incoming tag is wrapped rather than recomputed from scratch here.
+    let tag = SuiteHeaderIntegrityMac::from_bytes(&integrity_mac);
+    let recomputed_mac = S::Mac::compute(integrity_mac_key, &enc_routing_info);
+    constant_time_eq(recomputed_mac.get_value_ref(), tag.get_value_ref())
 }
 
 pub fn decrypt_padded_routing_info(
     key: &StreamCipherKey,
     padded_routing_info: &[u8],
 ) -> PaddedRoutingInformation {
-    let pseudorandom_bytes = crypto::generate_pseudorandom_bytes(
-        &key,
-        &crypto::STREAM_CIPHER_INIT_VECTOR,
-        STREAM_CIPHER_OUTPUT_LENGTH,
-    );
+    decrypt_padded_routing_info_with_suite::<DefaultSuite>(key, padded_routing_info)
+}
+
+fn decrypt_padded_routing_info_with_suite<S: SphinxSuite>(
+    key: &StreamCipherKey,
+    padded_routing_info: &[u8],
+) -> PaddedRoutingInformation {
+    let pseudorandom_bytes = S::StreamCipher::generate_keystream(key, STREAM_CIPHER_OUTPUT_LENGTH);
 
-    let lenx = padded_routing_info.len();
     let decrypted_routing_info_vec = utils::bytes::xor(&padded_routing_info, &pseudorandom_bytes);
 
     let mut decrypted_routing_info = [0u8; ROUTING_INFO_SIZE + 3 * SECURITY_PARAMETER];
@@ -139,3 +287,125 @@ mod check_decryption {
             .eq(routing_info.iter()));
     }
 }
+
+#[cfg(test)]
+mod parsing_unwrapped_routing_information {
+    use super::*;
+
+    fn dummy_shared_secret() -> curve25519_dalek::montgomery::MontgomeryPoint {
+        curve25519_dalek::montgomery::MontgomeryPoint([0u8; 32])
+    }
+
+    fn current_timestamp() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn well_formed_routing_info(address: &[u8], delay: f64, timestamp: u64) -> Vec<u8> {
+        let mac = [7u8; INTEGRITY_MAC_SIZE];
+        let next_enc_header = [9u8; ROUTING_INFO_SIZE];
+        [
+            address.to_vec(),
+            delay.to_le_bytes().to_vec(),
+            timestamp.to_le_bytes().to_vec(),
+            mac.to_vec(),
+            next_enc_header.to_vec(),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn it_returns_an_error_if_the_blob_is_too_short() {
+        let too_short = vec![1u8; MIN_PARSEABLE_ROUTING_INFO_LENGTH - 1];
+        match parse_unwrapped_routing_information(
+            &too_short,
+            dummy_shared_secret(),
+            Duration::from_secs(300),
+        ) {
+            Err(ProcessingError::InvalidRoutingInformationLengthError) => {}
+            _ => panic!("Should have rejected an undersized routing information blob"),
+        }
+    }
+
+    #[test]
+    fn it_recognises_the_all_zero_address_as_the_final_hop() {
+        let address = [0u8; SECURITY_PARAMETER];
+        let routing_info = well_formed_routing_info(&address, 4.2, current_timestamp());
+
+        match parse_unwrapped_routing_information(
+            &routing_info,
+            dummy_shared_secret(),
+            Duration::from_secs(300),
+        ) {
+            Ok(ProcessedHop::FinalHop) => {}
+            _ => panic!("Should have recognised the destination marker"),
+        }
+    }
+
+    #[test]
+    fn it_parses_a_forward_hop_and_recovers_its_delay() {
+        let address = [3u8; SECURITY_PARAMETER];
+        let routing_info = well_formed_routing_info(&address, 4.2, current_timestamp());
+
+        match parse_unwrapped_routing_information(
+            &routing_info,
+            dummy_shared_secret(),
+            Duration::from_secs(300),
+        ) {
+            Ok(ProcessedHop::ForwardHop(_, hop)) => assert_eq!(4.2, hop.delay),
+            _ => panic!("Should have parsed a forward hop"),
+        }
+    }
+
+    #[test]
+    fn it_rejects_a_packet_whose_timestamp_has_expired() {
+        let address = [3u8; SECURITY_PARAMETER];
+        let stale_timestamp = current_timestamp() - 3600;
+        let routing_info = well_formed_routing_info(&address, 4.2, stale_timestamp);
+
+        match parse_unwrapped_routing_information(
+            &routing_info,
+            dummy_shared_secret(),
+            Duration::from_secs(300),
+        ) {
+            Err(ProcessingError::InvalidTimestamp) => {}
+            _ => panic!("Should have rejected an expired packet"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod computing_the_replay_tag {
+    use super::*;
+
+    fn header_with_secret(
+        shared_secret: curve25519_dalek::montgomery::MontgomeryPoint,
+    ) -> SphinxHeader {
+        SphinxHeader {
+            shared_secret,
+            routing_info: routing::RoutingInfo {
+                enc_header: [0u8; ROUTING_INFO_SIZE],
+                header_integrity_hmac: [0u8; INTEGRITY_MAC_SIZE],
+            },
+        }
+    }
+
+    #[test]
+    fn it_is_deterministic_for_the_same_shared_secret() {
+        let secret = curve25519_dalek::montgomery::MontgomeryPoint([5u8; 32]);
+        let header_a = header_with_secret(secret);
+        let header_b = header_with_secret(secret);
+
+        assert_eq!(header_a.replay_tag(), header_b.replay_tag());
+    }
+
+    #[test]
+    fn it_differs_across_shared_secrets() {
+        let header_a = header_with_secret(curve25519_dalek::montgomery::MontgomeryPoint([5u8; 32]));
+        let header_b = header_with_secret(curve25519_dalek::montgomery::MontgomeryPoint([6u8; 32]));
+
+        assert_ne!(header_a.replay_tag(), header_b.replay_tag());
+    }
+}
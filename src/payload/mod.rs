@@ -1,13 +1,136 @@
+use std::io::{Read, Write};
+
 use arrayref::array_ref;
 use blake2::VarBlake2b;
 // we might want to swap this one with a different implementation
 use chacha::ChaCha;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
 use lioness::{Lioness, RAW_KEY_SIZE};
 
-use crate::constants::{PAYLOAD_KEY_SIZE, PAYLOAD_SIZE, SECURITY_PARAMETER};
+use crate::constants::{
+    DESTINATION_ADDRESS_LENGTH, PAYLOAD_KEY_SIZE, PAYLOAD_SIZE, SECURITY_PARAMETER,
+};
+use crate::crypto;
 use crate::header::keys::PayloadKey;
 use crate::route::DestinationAddressBytes;
 use crate::ProcessingError;
+use zeroize::Zeroizing;
+
+// the tag is truncated to the same length as the other in-protocol security parameters
+const PAYLOAD_TAG_SIZE: usize = SECURITY_PARAMETER;
+
+// current on-the-wire packet format version; bumped whenever the prefix or payload layout changes
+pub const PACKET_VERSION: u8 = 1;
+
+// a single leading byte carrying `(version << 4) | packet_type`, read before any decryption
+// is attempted so mixes don't have to guess the packet's purpose from its length alone
+const PACKET_PREFIX_SIZE: usize = 1;
+
+/// Discriminates the purpose of a Sphinx packet so a node can branch on it without having
+/// to decrypt the payload - modelled on QUIC's combined version-and-type header byte.
+///
+/// This only discriminates the *payload*: `Payload::from_bytes`/`to_bytes` can read it off
+/// before any Lioness decryption happens, but a forwarding mix node still has to unwrap the
+/// Sphinx header first to learn whether it's the final hop at all. Stamping the same kind of
+/// discriminator onto the header itself - so an intermediate node could drop loop/cover traffic
+/// without finishing the header-unwrap - would need the header's own wire format and builder
+/// (`crate::header`) to carry it, which is a separate change from this one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    /// packet still has at least one more mix hop to traverse
+    Forward = 0,
+    /// packet has reached its destination and carries a real message
+    Final = 1,
+    /// packet is a reply built from a previously received reply block
+    Reply = 2,
+    /// loop/cover traffic that a node may drop without delivering anywhere
+    Cover = 3,
+}
+
+impl PacketType {
+    fn from_nibble(nibble: u8) -> Result<Self, ProcessingError> {
+        match nibble {
+            0 => Ok(PacketType::Forward),
+            1 => Ok(PacketType::Final),
+            2 => Ok(PacketType::Reply),
+            3 => Ok(PacketType::Cover),
+            _ => Err(ProcessingError::UnknownPacketType),
+        }
+    }
+
+    fn as_nibble(self) -> u8 {
+        self as u8
+    }
+}
+
+fn encode_packet_prefix(packet_type: PacketType) -> u8 {
+    (PACKET_VERSION << 4) | packet_type.as_nibble()
+}
+
+fn decode_packet_prefix(prefix: u8) -> Result<PacketType, ProcessingError> {
+    let version = prefix >> 4;
+    if version != PACKET_VERSION {
+        return Err(ProcessingError::UnsupportedVersion);
+    }
+    PacketType::from_nibble(prefix & 0x0f)
+}
+
+// one byte right after the destination address: 1 if `message` below was deflate-compressed
+// before encryption, 0 if it's carried as-is
+const COMPRESSION_MARKER_SIZE: usize = 1;
+const COMPRESSED: u8 = 1;
+const UNCOMPRESSED: u8 = 0;
+
+// a big-endian length, right after the compression marker, of the (possibly compressed)
+// message that follows - without it there would be nothing distinguishing the real message
+// from the zero padding appended after it to bring the layer up to `PAYLOAD_SIZE`
+const MESSAGE_LENGTH_FIELD_SIZE: usize = 2;
+
+// compresses `message`, following the compress-then-encrypt approach of only paying for
+// compression when it actually shrinks the data - otherwise we'd risk the compressed form
+// no longer fitting inside the fixed-size payload
+fn maybe_compress(message: &[u8]) -> (u8, Vec<u8>) {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(message)
+        .expect("compressing into an in-memory buffer cannot fail");
+    let compressed = encoder
+        .finish()
+        .expect("compressing into an in-memory buffer cannot fail");
+
+    if compressed.len() < message.len() {
+        (COMPRESSED, compressed)
+    } else {
+        (UNCOMPRESSED, message.to_vec())
+    }
+}
+
+fn decompress(bytes: &[u8]) -> Result<Vec<u8>, ProcessingError> {
+    let mut decoder = DeflateDecoder::new(bytes);
+    let mut decompressed = Vec::new();
+    decoder
+        .read_to_end(&mut decompressed)
+        .map_err(|_| ProcessingError::DecompressionError)?;
+    Ok(decompressed)
+}
+
+// derives the end-to-end authentication tag that only the destination (holder of
+// `final_payload_key`) is able to compute, binding the payload to its intended recipient
+fn compute_payload_tag(
+    final_payload_key: &PayloadKey,
+    destination_address_bytes: &[u8],
+    message_and_padding: &[u8],
+) -> Vec<u8> {
+    let mut tag_input = destination_address_bytes.to_vec();
+    tag_input.extend_from_slice(message_and_padding);
+
+    let mut tag =
+        crypto::compute_keyed_hmac(Zeroizing::new(final_payload_key.to_vec()), &tag_input);
+    tag.truncate(PAYLOAD_TAG_SIZE);
+    tag
+}
 
 // we might want to swap this one with a different implementation
 pub struct Payload {
@@ -15,6 +138,7 @@ pub struct Payload {
     // as in theory everything will have a constant size which we already know.
     // For now we'll stick with Vectors.
     content: Vec<u8>,
+    packet_type: PacketType,
 }
 
 impl Payload {
@@ -22,15 +146,20 @@ impl Payload {
         plaintext_message: &[u8],
         payload_keys: &[PayloadKey],
         destination_address: DestinationAddressBytes,
-    ) -> Self {
+        packet_type: PacketType,
+    ) -> Result<Self, ProcessingError> {
         let final_payload_key = payload_keys
             .last()
             .expect("The keys should be already initialized");
         // encapsulate_most_inner_payload
-        let final_payload_layer =
-            Self::encrypt_final_layer(plaintext_message, final_payload_key, destination_address);
-
-        Self::encrypt_outer_layers(final_payload_layer, payload_keys)
+        let final_payload_layer = Self::encrypt_final_layer(
+            plaintext_message,
+            final_payload_key,
+            destination_address,
+            packet_type,
+        )?;
+
+        Ok(Self::encrypt_outer_layers(final_payload_layer, payload_keys))
     }
 
     // this is expected to get called after unwrapping all layers so it should be fine to get ownership of the content
@@ -43,28 +172,69 @@ impl Payload {
         self.content.as_ref()
     }
 
+    pub fn packet_type(&self) -> PacketType {
+        self.packet_type
+    }
+
+    /// Serializes the payload together with its version-and-type prefix, ready to be placed
+    /// on the wire alongside the Sphinx header.
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = vec![encode_packet_prefix(self.packet_type)];
+        bytes.extend(self.content);
+        bytes
+    }
+
     // in this context final means most inner layer
     fn encrypt_final_layer(
         message: &[u8],
         final_payload_key: &PayloadKey,
         destination_address: DestinationAddressBytes,
-    ) -> Self {
-        // generate zero-padding
-        let zero_bytes = vec![0u8; SECURITY_PARAMETER];
+        packet_type: PacketType,
+    ) -> Result<Self, ProcessingError> {
+        let (compression_marker, message) = maybe_compress(message);
 
         let destination_address_length = destination_address.len();
         let message_length = message.len();
-        let padding_length =
-            PAYLOAD_SIZE - SECURITY_PARAMETER - destination_address_length - message_length;
+        let fixed_overhead = SECURITY_PARAMETER
+            + destination_address_length
+            + COMPRESSION_MARKER_SIZE
+            + MESSAGE_LENGTH_FIELD_SIZE;
+
+        // even after (optional) compression, the message has to fit inside a single fixed-size
+        // payload alongside the tag, destination address and framing fields above - there's no
+        // way to split a message across payloads, so anything that still doesn't fit has to be
+        // rejected here rather than underflowing the padding length below
+        if message_length > PAYLOAD_SIZE - fixed_overhead {
+            return Err(ProcessingError::MessageTooLongError);
+        }
+        let padding_length = PAYLOAD_SIZE - fixed_overhead - message_length;
 
         let padding = vec![0u8; padding_length];
-        // concatenate security zero padding with destination and message and additional length padding
-        let mut final_payload: Vec<u8> = zero_bytes
+        // everything that follows the destination address is authenticated by the tag below;
+        // the explicit length lets `recover_plaintext` find the message's real end again,
+        // rather than assuming it fills everything up to the padding
+        let message_and_padding: Vec<u8> = [compression_marker]
+            .iter()
+            .cloned()
+            .chain((message_length as u16).to_be_bytes().iter().cloned())
+            .chain(message)
+            .chain(padding)
+            .collect();
+
+        // end-to-end authentication tag, replacing the old implicit zero-prefix check -
+        // only the destination, who knows `final_payload_key`, can compute or verify it
+        let tag = compute_payload_tag(
+            final_payload_key,
+            &destination_address.to_vec(),
+            &message_and_padding,
+        );
+
+        // concatenate the tag with destination and message and additional length padding
+        let mut final_payload: Vec<u8> = tag
             .iter()
             .cloned()
             .chain(destination_address.to_vec().iter().cloned())
-            .chain(message.iter().cloned())
-            .chain(padding.iter().cloned())
+            .chain(message_and_padding.iter().cloned())
             .collect();
 
         // encrypt the padded plaintext using the payload key
@@ -72,9 +242,12 @@ impl Payload {
             Lioness::<VarBlake2b, ChaCha>::new_raw(array_ref!(final_payload_key, 0, RAW_KEY_SIZE));
         lioness_cipher.encrypt(&mut final_payload).unwrap();
 
-        Payload {
+        // the caller decides what this packet is for (a real message, a reply, cover traffic,
+        // ...); this layer just carries that decision along rather than assuming one
+        Ok(Payload {
             content: final_payload,
-        }
+            packet_type,
+        })
     }
 
     fn encrypt_outer_layers(final_payload_layer: Self, route_payload_keys: &[PayloadKey]) -> Self {
@@ -99,6 +272,7 @@ impl Payload {
 
         Payload {
             content: payload_content,
+            packet_type: current_layer.packet_type,
         }
     }
 
@@ -109,17 +283,54 @@ impl Payload {
         lioness_cipher.decrypt(&mut payload_content).unwrap();
         Payload {
             content: payload_content,
+            packet_type: self.packet_type,
         }
     }
 
     pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, ProcessingError> {
+        if bytes.len() < PACKET_PREFIX_SIZE + PAYLOAD_SIZE {
+            return Err(ProcessingError::InvalidPayloadLengthError);
+        }
+
+        let packet_type = decode_packet_prefix(bytes[0])?;
+
         // TODO: currently it's defined as minimum size. It should be always constant length in the future
         // once we decide on payload size
-        if bytes.len() < PAYLOAD_SIZE {
-            return Err(ProcessingError::InvalidPayloadLengthError);
+        Ok(Payload {
+            content: bytes[PACKET_PREFIX_SIZE..].to_vec(),
+            packet_type,
+        })
+    }
+
+    // to be called once every onion layer has been unwrapped; verifies the tag computed
+    // by the original sender and, on success, returns the plaintext message
+    pub fn recover_plaintext(self, final_payload_key: &PayloadKey) -> Result<Vec<u8>, ProcessingError> {
+        let tag = &self.content[..PAYLOAD_TAG_SIZE];
+        let destination_address_bytes =
+            &self.content[PAYLOAD_TAG_SIZE..PAYLOAD_TAG_SIZE + DESTINATION_ADDRESS_LENGTH];
+        let message_and_padding = &self.content[PAYLOAD_TAG_SIZE + DESTINATION_ADDRESS_LENGTH..];
+
+        let mut tag_input = destination_address_bytes.to_vec();
+        tag_input.extend_from_slice(message_and_padding);
+        if !crypto::verify_keyed_hmac(Zeroizing::new(final_payload_key.to_vec()), &tag_input, tag) {
+            return Err(ProcessingError::InvalidPayloadTag);
         }
 
-        Ok(Payload { content: bytes })
+        let (compression_marker, rest) = message_and_padding
+            .split_first()
+            .ok_or(ProcessingError::InvalidPayloadLengthError)?;
+        let length_bytes = rest
+            .get(..MESSAGE_LENGTH_FIELD_SIZE)
+            .ok_or(ProcessingError::InvalidPayloadLengthError)?;
+        let message_length = u16::from_be_bytes([length_bytes[0], length_bytes[1]]) as usize;
+        let message = rest[MESSAGE_LENGTH_FIELD_SIZE..]
+            .get(..message_length)
+            .ok_or(ProcessingError::InvalidPayloadLengthError)?;
+
+        match *compression_marker {
+            COMPRESSED => decompress(message),
+            _ => Ok(message.to_vec()),
+        }
     }
 }
 
@@ -146,6 +357,67 @@ mod building_payload_from_bytes {
     //         _ => panic!("Should have returned an error when packet bytes too long"),
     //     };
     // }
+
+    #[test]
+    fn from_bytes_rejects_an_unsupported_version() {
+        let mut bytes = vec![0xffu8; PACKET_PREFIX_SIZE + PAYLOAD_SIZE];
+        bytes[0] = 0xf1; // version 15, type 1 (Final)
+        let expected = ProcessingError::UnsupportedVersion;
+        match Payload::from_bytes(bytes) {
+            Err(err) => assert_eq!(expected, err),
+            _ => panic!("Should have returned an error for an unrecognised version"),
+        };
+    }
+
+    #[test]
+    fn from_bytes_rejects_an_unknown_packet_type() {
+        let mut bytes = vec![0xffu8; PACKET_PREFIX_SIZE + PAYLOAD_SIZE];
+        bytes[0] = (PACKET_VERSION << 4) | 0x0f; // valid version, bogus type nibble
+        let expected = ProcessingError::UnknownPacketType;
+        match Payload::from_bytes(bytes) {
+            Err(err) => assert_eq!(expected, err),
+            _ => panic!("Should have returned an error for an unrecognised packet type"),
+        };
+    }
+
+    #[test]
+    fn from_bytes_recovers_the_stamped_packet_type() {
+        let mut bytes = vec![0xffu8; PACKET_PREFIX_SIZE + PAYLOAD_SIZE];
+        bytes[0] = encode_packet_prefix(PacketType::Forward);
+        let payload = Payload::from_bytes(bytes).unwrap();
+        assert_eq!(PacketType::Forward, payload.packet_type());
+    }
+}
+
+#[cfg(test)]
+mod packet_prefix_round_trip {
+    use super::*;
+
+    #[test]
+    fn encapsulating_a_message_stamps_the_requested_packet_type() {
+        let payload = Payload::encrypt_final_layer(
+            &[1u8, 2, 3],
+            &[7u8; PAYLOAD_KEY_SIZE],
+            crate::route::destination_address_fixture(),
+            PacketType::Final,
+        )
+        .unwrap();
+        assert_eq!(PacketType::Final, payload.packet_type());
+    }
+
+    #[test]
+    fn to_bytes_then_from_bytes_preserves_the_packet_type() {
+        let payload = Payload::encrypt_final_layer(
+            &[1u8, 2, 3],
+            &[7u8; PAYLOAD_KEY_SIZE],
+            crate::route::destination_address_fixture(),
+            PacketType::Reply,
+        )
+        .unwrap();
+        let bytes = payload.to_bytes();
+        let recovered = Payload::from_bytes(bytes).unwrap();
+        assert_eq!(PacketType::Reply, recovered.packet_type());
+    }
 }
 
 #[cfg(test)]
@@ -162,11 +434,37 @@ mod test_encrypting_final_payload {
         let message_len = message.len();
         let destination = destination_address_fixture();
         let routing_keys = routing_keys_fixture();
-        let final_enc_payload =
-            Payload::encrypt_final_layer(&message, &routing_keys.payload_key, destination);
+        let final_enc_payload = Payload::encrypt_final_layer(
+            &message,
+            &routing_keys.payload_key,
+            destination,
+            PacketType::Final,
+        )
+        .unwrap();
 
         assert_eq!(PAYLOAD_SIZE, final_enc_payload.content.len());
     }
+
+    #[test]
+    fn it_rejects_a_message_too_large_to_fit_in_a_single_payload() {
+        // incompressible (random-looking, non-repetitive) so `maybe_compress` can't shrink it
+        // down to something that would fit
+        let message: Vec<u8> = (0..PAYLOAD_SIZE as u32).map(|i| i as u8).collect();
+        let destination = destination_address_fixture();
+        let routing_keys = routing_keys_fixture();
+
+        let result = Payload::encrypt_final_layer(
+            &message,
+            &routing_keys.payload_key,
+            destination,
+            PacketType::Final,
+        );
+
+        assert_eq!(
+            ProcessingError::MessageTooLongError,
+            result.unwrap_err()
+        );
+    }
 }
 
 #[cfg(test)]
@@ -186,7 +484,13 @@ mod test_encapsulating_payload {
         let payload_key_3 = [5u8; PAYLOAD_KEY_SIZE];
         let payload_keys = vec![payload_key_1, payload_key_2, payload_key_3];
 
-        let final_enc_payload = Payload::encrypt_final_layer(&message, &payload_key_1, destination);
+        let final_enc_payload = Payload::encrypt_final_layer(
+            &message,
+            &payload_key_1,
+            destination,
+            PacketType::Final,
+        )
+        .unwrap();
         let payload_encapsulation = Payload::encrypt_outer_layers(final_enc_payload, &payload_keys);
         assert_eq!(PAYLOAD_SIZE, payload_encapsulation.content.len());
     }
@@ -208,7 +512,9 @@ mod test_unwrapping_payload {
         let payload_key_3 = [5u8; PAYLOAD_KEY_SIZE];
         let payload_keys = [payload_key_1, payload_key_2, payload_key_3];
 
-        let encrypted_payload = Payload::encapsulate_message(&message, &payload_keys, destination);
+        let encrypted_payload =
+            Payload::encapsulate_message(&message, &payload_keys, destination, PacketType::Final)
+                .unwrap();
 
         let unwrapped_payload = payload_keys
             .iter()
@@ -216,16 +522,123 @@ mod test_unwrapping_payload {
                 current_layer.unwrap(payload_key)
             });
 
-        let zero_bytes = vec![0u8; SECURITY_PARAMETER];
-        let additional_padding =
-            vec![0u8; PAYLOAD_SIZE - SECURITY_PARAMETER - message.len() - destination.len()];
-        let expected_payload = [
-            zero_bytes,
-            destination.to_vec(),
+        let additional_padding = vec![
+            0u8;
+            PAYLOAD_SIZE
+                - SECURITY_PARAMETER
+                - COMPRESSION_MARKER_SIZE
+                - MESSAGE_LENGTH_FIELD_SIZE
+                - message.len()
+                - destination.len()
+        ];
+        // the message is too small for deflate to ever shrink it, so it's carried uncompressed
+        let message_and_padding = [
+            vec![UNCOMPRESSED],
+            (message.len() as u16).to_be_bytes().to_vec(),
             message,
             additional_padding,
         ]
         .concat();
+        let expected_tag =
+            compute_payload_tag(&payload_key_3, &destination.to_vec(), &message_and_padding);
+        let expected_payload = [expected_tag, destination.to_vec(), message_and_padding].concat();
         assert_eq!(expected_payload, unwrapped_payload.get_content());
     }
 }
+
+#[cfg(test)]
+mod test_recovering_plaintext {
+    use crate::constants::PAYLOAD_KEY_SIZE;
+    use crate::route::destination_address_fixture;
+
+    use super::*;
+
+    #[test]
+    fn it_recovers_the_original_message_when_the_tag_is_untampered() {
+        let message = vec![1u8, 16];
+        let destination = destination_address_fixture();
+        let payload_key = [3u8; PAYLOAD_KEY_SIZE];
+
+        let encrypted_payload =
+            Payload::encrypt_final_layer(&message, &payload_key, destination, PacketType::Final)
+                .unwrap();
+        let unwrapped_payload = encrypted_payload.unwrap(&payload_key);
+
+        assert_eq!(
+            message,
+            unwrapped_payload.recover_plaintext(&payload_key).unwrap()
+        );
+    }
+
+    #[test]
+    fn it_transparently_decompresses_a_message_that_compresses_well() {
+        let message = vec![42u8; 200];
+        let destination = destination_address_fixture();
+        let payload_key = [3u8; PAYLOAD_KEY_SIZE];
+
+        let encrypted_payload =
+            Payload::encrypt_final_layer(&message, &payload_key, destination, PacketType::Final)
+                .unwrap();
+        let unwrapped_payload = encrypted_payload.unwrap(&payload_key);
+
+        assert_eq!(
+            message,
+            unwrapped_payload.recover_plaintext(&payload_key).unwrap()
+        );
+    }
+
+    #[test]
+    fn it_rejects_a_payload_whose_tag_was_tampered_with() {
+        let message = vec![1u8, 16];
+        let destination = destination_address_fixture();
+        let payload_key = [3u8; PAYLOAD_KEY_SIZE];
+
+        let encrypted_payload =
+            Payload::encrypt_final_layer(&message, &payload_key, destination, PacketType::Final)
+                .unwrap();
+        let mut unwrapped_payload = encrypted_payload.unwrap(&payload_key);
+        unwrapped_payload.content[0] ^= 0xff;
+
+        assert_eq!(
+            ProcessingError::InvalidPayloadTag,
+            unwrapped_payload.recover_plaintext(&payload_key).unwrap_err()
+        );
+    }
+}
+
+#[cfg(test)]
+mod compressing_the_message {
+    use super::*;
+
+    #[test]
+    fn it_leaves_incompressible_data_untouched() {
+        let message = vec![1u8, 16];
+        let (marker, result) = maybe_compress(&message);
+        assert_eq!(UNCOMPRESSED, marker);
+        assert_eq!(message, result);
+    }
+
+    #[test]
+    fn it_shrinks_highly_repetitive_data() {
+        let message = vec![7u8; 500];
+        let (marker, result) = maybe_compress(&message);
+        assert_eq!(COMPRESSED, marker);
+        assert!(result.len() < message.len());
+    }
+
+    #[test]
+    fn decompress_recovers_what_maybe_compress_produced() {
+        let message = vec![7u8; 500];
+        let (_, compressed) = maybe_compress(&message);
+        assert_eq!(message, decompress(&compressed).unwrap());
+    }
+
+    #[test]
+    fn decompress_rejects_malformed_input() {
+        let garbage = vec![0xffu8; 32];
+        assert_eq!(
+            ProcessingError::DecompressionError,
+            decompress(&garbage).unwrap_err()
+        );
+    }
+}